@@ -0,0 +1,184 @@
+//! Backend trait the FUSE and WebDAV frontends both drive.
+//!
+//! This holds the path-based filesystem logic — resolving entries,
+//! reading/writing file contents (transparently through the encryption
+//! layer when configured), and listing directories. Inode bookkeeping is a
+//! FUSE-specific concern and stays in `PassthroughFS`; WebDAV addresses
+//! everything by path instead.
+
+use crate::crypto;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A backend-agnostic stand-in for `fuser::FileAttr`, minus the `ino`,
+/// `ctime`, `crtime` and `flags` fields (frontends that need inode numbers
+/// assign those themselves).
+#[derive(Clone, Copy)]
+pub struct Attr {
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub kind: fuser::FileType,
+    pub perm: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub blksize: u32,
+}
+
+pub struct DirEntry {
+    pub name: OsString,
+    pub path: PathBuf,
+    pub attr: Attr,
+}
+
+/// Path and attr logic shared by the FUSE and WebDAV frontends.
+pub trait Backend: Send + Sync {
+    fn lookup(&self, parent: &Path, name: &std::ffi::OsStr) -> Result<PathBuf, i32>;
+    fn getattr(&self, path: &Path) -> Result<Attr, i32>;
+    fn read(&self, path: &Path, offset: u64, size: u32) -> Result<Vec<u8>, i32>;
+    fn readdir(&self, path: &Path) -> Result<Vec<DirEntry>, i32>;
+    fn write(&self, path: &Path, offset: u64, data: &[u8]) -> Result<u32, i32>;
+    /// Creates `path` as an empty (regular, or empty-header encrypted) file
+    /// if it doesn't already exist. A no-op if the file is already present.
+    fn create(&self, path: &Path) -> Result<(), i32>;
+}
+
+fn attr_from_metadata(backend: &FsBackend, path: &Path, metadata: &fs::Metadata) -> Attr {
+    let ft = metadata.file_type();
+    let kind = if ft.is_dir() {
+        fuser::FileType::Directory
+    } else if ft.is_symlink() {
+        fuser::FileType::Symlink
+    } else if ft.is_block_device() {
+        fuser::FileType::BlockDevice
+    } else if ft.is_char_device() {
+        fuser::FileType::CharDevice
+    } else if ft.is_fifo() {
+        fuser::FileType::NamedPipe
+    } else if ft.is_socket() {
+        fuser::FileType::Socket
+    } else {
+        fuser::FileType::RegularFile
+    };
+
+    let size = if kind == fuser::FileType::RegularFile {
+        match &backend.cipher {
+            Some(cipher) => cipher.plaintext_len(path).unwrap_or(0),
+            None => metadata.len(),
+        }
+    } else {
+        metadata.len()
+    };
+
+    Attr {
+        size,
+        blocks: metadata.blocks(),
+        atime: metadata.accessed().unwrap_or(UNIX_EPOCH),
+        mtime: metadata.modified().unwrap_or(UNIX_EPOCH),
+        kind,
+        perm: metadata.mode() as u16,
+        nlink: metadata.nlink() as u32,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        rdev: metadata.rdev() as u32,
+        blksize: metadata.blksize() as u32,
+    }
+}
+
+/// The on-disk, optionally-encrypted backend: plain `std::fs` calls routed
+/// through the cipher when one is configured.
+pub struct FsBackend {
+    pub source: PathBuf,
+    pub cipher: Option<crypto::FileCipher>,
+}
+
+impl FsBackend {
+    pub fn new(source: PathBuf, cipher: Option<crypto::FileCipher>) -> Self {
+        FsBackend { source, cipher }
+    }
+}
+
+impl Backend for FsBackend {
+    fn lookup(&self, parent: &Path, name: &std::ffi::OsStr) -> Result<PathBuf, i32> {
+        let path = parent.join(name);
+        if path.symlink_metadata().is_err() {
+            return Err(libc::ENOENT);
+        }
+        Ok(path)
+    }
+
+    fn getattr(&self, path: &Path) -> Result<Attr, i32> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) => Ok(attr_from_metadata(self, path, &metadata)),
+            Err(_) => Err(libc::ENOENT),
+        }
+    }
+
+    fn read(&self, path: &Path, offset: u64, size: u32) -> Result<Vec<u8>, i32> {
+        if let Some(cipher) = &self.cipher {
+            return cipher.read(path, offset, size).map_err(|_| libc::EIO);
+        }
+
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = fs::File::open(path).map_err(|_| libc::ENOENT)?;
+        file.seek(SeekFrom::Start(offset)).map_err(|_| libc::EIO)?;
+        let mut buffer = vec![0; size as usize];
+        let n = file.read(&mut buffer).map_err(|_| libc::EIO)?;
+        buffer.truncate(n);
+        Ok(buffer)
+    }
+
+    fn readdir(&self, path: &Path) -> Result<Vec<DirEntry>, i32> {
+        let entries = fs::read_dir(path).map_err(|_| libc::ENOENT)?;
+
+        let mut out = Vec::new();
+        for entry in entries.filter_map(|e| e.ok()) {
+            if let Ok(metadata) = entry.metadata() {
+                let entry_path = entry.path();
+                out.push(DirEntry {
+                    name: entry.file_name(),
+                    attr: attr_from_metadata(self, &entry_path, &metadata),
+                    path: entry_path,
+                });
+            }
+        }
+        Ok(out)
+    }
+
+    fn write(&self, path: &Path, offset: u64, data: &[u8]) -> Result<u32, i32> {
+        if let Some(cipher) = &self.cipher {
+            return cipher.write(path, offset, data).map_err(|_| libc::EIO);
+        }
+
+        use std::io::{Seek, SeekFrom, Write};
+        let mut file = fs::OpenOptions::new().write(true).open(path).map_err(|_| libc::ENOENT)?;
+        file.seek(SeekFrom::Start(offset)).map_err(|_| libc::EIO)?;
+        file.write(data).map(|n| n as u32).map_err(|_| libc::EIO)
+    }
+
+    fn create(&self, path: &Path) -> Result<(), i32> {
+        if path.symlink_metadata().is_ok() {
+            return Ok(());
+        }
+
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+
+        if let Some(cipher) = &self.cipher {
+            if cipher.plaintext_len(path).is_err() {
+                cipher.init_file(path).map_err(|_| libc::EIO)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,522 @@
+//! Per-file authenticated encryption for the backing store.
+//!
+//! Each plaintext file is stored on disk as a small header followed by a
+//! sequence of fixed-size blocks, each sealed independently with an AEAD
+//! cipher so that random-access reads only need to decrypt the blocks that
+//! cover the requested range.
+//!
+//! On-disk file layout:
+//!   [ FileHeader ][ block 0 (generation + BLOCK_SIZE + TAG_SIZE) ][ block 1 ]...
+//!
+//! Every block carries its own write-generation counter alongside the
+//! ciphertext. The AEAD nonce is derived from the file's random nonce base,
+//! the block index, *and* that generation counter, so re-sealing a block
+//! (an overwrite, a truncate-edge rewrite, or the sparse-write gap sealer)
+//! never reuses a (key, nonce) pair, even though the block index and nonce
+//! base never change for the life of the file.
+//!
+//! The repo-wide master key is derived once from a passphrase via Argon2,
+//! using a salt stored in a header file kept in [`meta_dir`] — a sibling
+//! directory of the source tree, not inside it, so it can't be listed or
+//! deleted through the mount itself.
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const BLOCK_SIZE: usize = 4096;
+pub const TAG_SIZE: usize = 16;
+const NONCE_BASE_SIZE: usize = 8;
+const GEN_SIZE: usize = 8;
+const FILE_MAGIC: &[u8; 8] = b"KRPTOFS2";
+const HEADER_LEN: usize = 8 + NONCE_BASE_SIZE + 8;
+
+const REPO_HEADER_NAME: &str = ".kriptofs";
+const REPO_MAGIC: &[u8; 8] = b"KRPTOREP";
+const SALT_SIZE: usize = 16;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    Io(io::Error),
+    InvalidHeader,
+    AuthFailed,
+}
+
+impl From<io::Error> for CryptoError {
+    fn from(e: io::Error) -> Self {
+        CryptoError::Io(e)
+    }
+}
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoError::Io(e) => write!(f, "io error: {}", e),
+            CryptoError::InvalidHeader => write!(f, "invalid or missing file header"),
+            CryptoError::AuthFailed => write!(f, "authentication tag mismatch"),
+        }
+    }
+}
+
+/// Per-file header: magic, the random nonce base for this file, and the
+/// true plaintext length (the backing file's on-disk size includes header
+/// and per-block tag overhead, so it cannot be used directly).
+#[derive(Clone, Copy)]
+pub struct FileHeader {
+    pub nonce_base: [u8; NONCE_BASE_SIZE],
+    pub plaintext_len: u64,
+}
+
+impl FileHeader {
+    fn new(plaintext_len: u64) -> Self {
+        let mut nonce_base = [0u8; NONCE_BASE_SIZE];
+        getrandom::getrandom(&mut nonce_base).expect("getrandom failed");
+        FileHeader {
+            nonce_base,
+            plaintext_len,
+        }
+    }
+
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0u8; HEADER_LEN];
+        buf[0..8].copy_from_slice(FILE_MAGIC);
+        buf[8..8 + NONCE_BASE_SIZE].copy_from_slice(&self.nonce_base);
+        buf[8 + NONCE_BASE_SIZE..].copy_from_slice(&self.plaintext_len.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, CryptoError> {
+        if buf.len() < HEADER_LEN || &buf[0..8] != FILE_MAGIC {
+            return Err(CryptoError::InvalidHeader);
+        }
+        let mut nonce_base = [0u8; NONCE_BASE_SIZE];
+        nonce_base.copy_from_slice(&buf[8..8 + NONCE_BASE_SIZE]);
+        let mut len_bytes = [0u8; 8];
+        len_bytes.copy_from_slice(&buf[8 + NONCE_BASE_SIZE..]);
+        Ok(FileHeader {
+            nonce_base,
+            plaintext_len: u64::from_le_bytes(len_bytes),
+        })
+    }
+
+    /// Derives the per-block AEAD nonce from the file's nonce base, the
+    /// block index, and that block's write-generation counter, so sealing
+    /// the same block twice never reuses a nonce.
+    fn nonce_for_block(&self, block_index: u64, generation: u64) -> XNonce {
+        let mut nonce = [0u8; 24];
+        nonce[0..NONCE_BASE_SIZE].copy_from_slice(&self.nonce_base);
+        nonce[NONCE_BASE_SIZE..NONCE_BASE_SIZE + 8].copy_from_slice(&block_index.to_le_bytes());
+        nonce[NONCE_BASE_SIZE + 8..].copy_from_slice(&generation.to_le_bytes());
+        *XNonce::from_slice(&nonce)
+    }
+}
+
+/// Returns the directory KriptoFS keeps its own bookkeeping files in (the
+/// repo salt, the persisted inode index) — a sibling of `source`, never a
+/// descendant of it, so it never shows up in a `lookup`/`readdir` over the
+/// served tree and can't be deleted through the mount.
+pub fn meta_dir(source: &Path) -> PathBuf {
+    let name = source
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    source.with_file_name(format!(".{}.kriptofs-meta", name))
+}
+
+/// Derives the repo master key from a passphrase, creating (or reusing) the
+/// salt stored in `meta_dir(source)`.
+pub fn derive_repo_key(source: &Path, passphrase: &str) -> Result<XChaCha20Poly1305, CryptoError> {
+    let dir = meta_dir(source);
+    fs::create_dir_all(&dir)?;
+    let salt = load_or_create_salt(&dir.join(REPO_HEADER_NAME))?;
+
+    let mut key_bytes = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut key_bytes)
+        .map_err(|_| CryptoError::InvalidHeader)?;
+
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&key_bytes)))
+}
+
+fn load_or_create_salt(path: &Path) -> Result<[u8; SALT_SIZE], CryptoError> {
+    if let Ok(buf) = fs::read(path) {
+        if buf.len() == 8 + SALT_SIZE && &buf[0..8] == REPO_MAGIC {
+            let mut salt = [0u8; SALT_SIZE];
+            salt.copy_from_slice(&buf[8..]);
+            return Ok(salt);
+        }
+        return Err(CryptoError::InvalidHeader);
+    }
+
+    let mut salt = [0u8; SALT_SIZE];
+    getrandom::getrandom(&mut salt).expect("getrandom failed");
+
+    let mut buf = Vec::with_capacity(8 + SALT_SIZE);
+    buf.extend_from_slice(REPO_MAGIC);
+    buf.extend_from_slice(&salt);
+    fs::write(path, &buf)?;
+
+    Ok(salt)
+}
+
+/// Sealed per-file codec bound to the repo key; owns the block math used by
+/// `read`/`write` in `FsBackend`.
+pub struct FileCipher {
+    cipher: XChaCha20Poly1305,
+}
+
+impl FileCipher {
+    pub fn new(cipher: XChaCha20Poly1305) -> Self {
+        FileCipher { cipher }
+    }
+
+    /// Initializes a fresh, empty encrypted file by writing just its header.
+    pub fn init_file(&self, path: &Path) -> Result<(), CryptoError> {
+        let header = FileHeader::new(0);
+        fs::write(path, header.encode())?;
+        Ok(())
+    }
+
+    pub fn read_header(&self, file: &mut fs::File) -> Result<FileHeader, CryptoError> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut buf = [0u8; HEADER_LEN];
+        file.read_exact(&mut buf).map_err(|_| CryptoError::InvalidHeader)?;
+        FileHeader::decode(&buf)
+    }
+
+    fn write_header(&self, file: &mut fs::File, header: &FileHeader) -> Result<(), CryptoError> {
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header.encode())?;
+        Ok(())
+    }
+
+    fn block_offset(block_index: u64) -> u64 {
+        HEADER_LEN as u64 + block_index * (GEN_SIZE + BLOCK_SIZE + TAG_SIZE) as u64
+    }
+
+    fn block_aad(block_index: u64, generation: u64) -> [u8; 16] {
+        let mut aad = [0u8; 16];
+        aad[0..8].copy_from_slice(&block_index.to_le_bytes());
+        aad[8..16].copy_from_slice(&generation.to_le_bytes());
+        aad
+    }
+
+    fn decrypt_block(
+        &self,
+        header: &FileHeader,
+        block_index: u64,
+        generation: u64,
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let nonce = header.nonce_for_block(block_index, generation);
+        let aad = Self::block_aad(block_index, generation);
+        self.cipher
+            .decrypt(&nonce, Payload { msg: sealed, aad: &aad })
+            .map_err(|_| CryptoError::AuthFailed)
+    }
+
+    fn encrypt_block(&self, header: &FileHeader, block_index: u64, generation: u64, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = header.nonce_for_block(block_index, generation);
+        let aad = Self::block_aad(block_index, generation);
+        self.cipher
+            .encrypt(&nonce, Payload { msg: plaintext, aad: &aad })
+            .expect("encryption failed")
+    }
+
+    /// Reads `size` plaintext bytes starting at plaintext `offset`,
+    /// decrypting only the ciphertext blocks that cover the range.
+    pub fn read(&self, path: &Path, offset: u64, size: u32) -> Result<Vec<u8>, CryptoError> {
+        let mut file = fs::File::open(path)?;
+        let header = self.read_header(&mut file)?;
+
+        if offset >= header.plaintext_len {
+            return Ok(Vec::new());
+        }
+        let end = (offset + size as u64).min(header.plaintext_len);
+
+        let first_block = offset / BLOCK_SIZE as u64;
+        let last_block = (end.saturating_sub(1)) / BLOCK_SIZE as u64;
+
+        let mut out = Vec::with_capacity((end - offset) as usize);
+        for block_index in first_block..=last_block {
+            let (_, plaintext) = self.read_block(&mut file, &header, block_index)?;
+
+            let block_start = block_index * BLOCK_SIZE as u64;
+            let lo = offset.max(block_start) - block_start;
+            let hi = end.min(block_start + BLOCK_SIZE as u64) - block_start;
+            out.extend_from_slice(&plaintext[lo as usize..hi as usize]);
+        }
+
+        Ok(out)
+    }
+
+    /// Reads and decrypts the block at `block_index`, returning its stored
+    /// generation counter alongside the plaintext so callers re-sealing it
+    /// can derive the next, never-reused generation.
+    fn read_block(
+        &self,
+        file: &mut fs::File,
+        header: &FileHeader,
+        block_index: u64,
+    ) -> Result<(u64, Vec<u8>), CryptoError> {
+        file.seek(SeekFrom::Start(Self::block_offset(block_index)))?;
+
+        let mut gen_bytes = [0u8; GEN_SIZE];
+        file.read_exact(&mut gen_bytes)?;
+        let generation = u64::from_le_bytes(gen_bytes);
+
+        let remaining = header.plaintext_len - block_index * BLOCK_SIZE as u64;
+        let plain_len = remaining.min(BLOCK_SIZE as u64) as usize;
+
+        let mut sealed = vec![0u8; plain_len + TAG_SIZE];
+        file.read_exact(&mut sealed)?;
+
+        let plaintext = self.decrypt_block(header, block_index, generation, &sealed)?;
+        Ok((generation, plaintext))
+    }
+
+    /// Seals `plaintext` into block `block_index`, bumping its write
+    /// generation past `prior_generation` (or starting at 0 for a block
+    /// that has never been written) so the nonce is never reused.
+    fn write_block(
+        &self,
+        file: &mut fs::File,
+        header: &FileHeader,
+        block_index: u64,
+        prior_generation: Option<u64>,
+        plaintext: &[u8],
+    ) -> Result<(), CryptoError> {
+        let generation = prior_generation.map(|g| g.wrapping_add(1)).unwrap_or(0);
+        let sealed = self.encrypt_block(header, block_index, generation, plaintext);
+
+        file.seek(SeekFrom::Start(Self::block_offset(block_index)))?;
+        file.write_all(&generation.to_le_bytes())?;
+        file.write_all(&sealed)?;
+        Ok(())
+    }
+
+    /// Zero-fills and seals every block in `[start_block, end_block)`,
+    /// read-modify-writing blocks that still hold valid data (below
+    /// `old_len`) so their tail is padded with zeros rather than left as
+    /// raw, un-sealed bytes. Used to cover the gap left behind whenever a
+    /// write or truncate extends the file past its previous end, so a
+    /// later `read` over the gap sees POSIX sparse-file zeros instead of
+    /// hitting an invalid ciphertext block.
+    fn seal_zero_range(
+        &self,
+        file: &mut fs::File,
+        header: &FileHeader,
+        old_len: u64,
+        new_len: u64,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<(), CryptoError> {
+        for block_index in start_block..end_block {
+            let block_start = block_index * BLOCK_SIZE as u64;
+            let block_plain_len = new_len.saturating_sub(block_start).min(BLOCK_SIZE as u64) as usize;
+
+            let (block, prior_generation) = if block_start < old_len {
+                let (generation, mut existing) = self.read_block(file, header, block_index)?;
+                existing.resize(block_plain_len, 0);
+                (existing, Some(generation))
+            } else {
+                (vec![0u8; block_plain_len], None)
+            };
+
+            self.write_block(file, header, block_index, prior_generation, &block)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at plaintext `offset`, re-sealing affected blocks with
+    /// read-modify-write on partial edges, and updates the stored plaintext
+    /// length. Returns the number of bytes written.
+    pub fn write(&self, path: &Path, offset: u64, data: &[u8]) -> Result<u32, CryptoError> {
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let mut header = self.read_header(&mut file)?;
+
+        let old_len = header.plaintext_len;
+        let new_len = old_len.max(offset + data.len() as u64);
+
+        let first_block = offset / BLOCK_SIZE as u64;
+        let last_block = (offset + data.len() as u64).saturating_sub(1) / BLOCK_SIZE as u64;
+
+        // If this write starts beyond the old end of file, seal every block
+        // in between as zero-filled plaintext instead of leaving them
+        // untouched (which would otherwise be a raw, un-sealed hole).
+        let gap_start_block = if old_len == 0 { 0 } else { (old_len - 1) / BLOCK_SIZE as u64 };
+        self.seal_zero_range(&mut file, &header, old_len, new_len, gap_start_block, first_block)?;
+
+        for block_index in first_block..=last_block {
+            let block_start = block_index * BLOCK_SIZE as u64;
+            let block_plain_len = new_len
+                .saturating_sub(block_start)
+                .min(BLOCK_SIZE as u64) as usize;
+
+            let (mut block, prior_generation) = if block_index * (BLOCK_SIZE as u64) < header.plaintext_len {
+                let (generation, mut existing) = self.read_block(&mut file, &header, block_index)?;
+                existing.resize(block_plain_len, 0);
+                (existing, Some(generation))
+            } else {
+                (vec![0u8; block_plain_len], None)
+            };
+
+            let lo = offset.max(block_start) - block_start;
+            let hi = (offset + data.len() as u64).min(block_start + BLOCK_SIZE as u64) - block_start;
+            let src_lo = (block_start + lo) - offset;
+            let src_hi = (block_start + hi) - offset;
+            block[lo as usize..hi as usize].copy_from_slice(&data[src_lo as usize..src_hi as usize]);
+
+            self.write_block(&mut file, &header, block_index, prior_generation, &block)?;
+        }
+
+        header.plaintext_len = new_len;
+        self.write_header(&mut file, &header)?;
+
+        Ok(data.len() as u32)
+    }
+
+    /// Truncates the plaintext to `new_len`, rewriting the last block if the
+    /// new length lands mid-block.
+    pub fn truncate(&self, path: &Path, new_len: u64) -> Result<(), CryptoError> {
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let mut header = self.read_header(&mut file)?;
+
+        if new_len < header.plaintext_len && new_len % BLOCK_SIZE as u64 != 0 {
+            let block_index = new_len / BLOCK_SIZE as u64;
+            if block_index * (BLOCK_SIZE as u64) < header.plaintext_len {
+                let (generation, mut block) = self.read_block(&mut file, &header, block_index)?;
+                let keep = (new_len - block_index * BLOCK_SIZE as u64) as usize;
+                block.truncate(keep);
+                self.write_block(&mut file, &header, block_index, Some(generation), &block)?;
+            }
+        } else if new_len > header.plaintext_len {
+            // Growing the file: seal every newly-covered block as
+            // zero-filled plaintext so a later read doesn't land on a raw,
+            // un-sealed hole.
+            let old_len = header.plaintext_len;
+            let start_block = if old_len == 0 { 0 } else { (old_len - 1) / BLOCK_SIZE as u64 };
+            let end_block = new_len.div_ceil(BLOCK_SIZE as u64);
+            self.seal_zero_range(&mut file, &header, old_len, new_len, start_block, end_block)?;
+        }
+
+        header.plaintext_len = new_len;
+        self.write_header(&mut file, &header)?;
+
+        let last_block = new_len.div_ceil(BLOCK_SIZE as u64);
+        file.set_len(Self::block_offset(last_block))?;
+
+        Ok(())
+    }
+
+    pub fn plaintext_len(&self, path: &Path) -> Result<u64, CryptoError> {
+        let mut file = fs::File::open(path)?;
+        Ok(self.read_header(&mut file)?.plaintext_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn temp_file_path(name: &str) -> PathBuf {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("kriptofs-test-{}-{}-{}", std::process::id(), n, name))
+    }
+
+    fn test_cipher() -> FileCipher {
+        FileCipher::new(XChaCha20Poly1305::new(Key::from_slice(&[7u8; 32])))
+    }
+
+    #[test]
+    fn read_write_within_a_single_block() {
+        let cipher = test_cipher();
+        let path = temp_file_path("single-block");
+        cipher.init_file(&path).unwrap();
+
+        cipher.write(&path, 0, b"hello world").unwrap();
+        let out = cipher.read(&path, 0, 11).unwrap();
+        assert_eq!(out, b"hello world");
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn write_spanning_multiple_blocks_reads_back_intact() {
+        let cipher = test_cipher();
+        let path = temp_file_path("multi-block");
+        cipher.init_file(&path).unwrap();
+
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 17).map(|i| (i % 251) as u8).collect();
+        cipher.write(&path, 0, &data).unwrap();
+        let out = cipher.read(&path, 0, data.len() as u32).unwrap();
+        assert_eq!(out, data);
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn sparse_write_past_eof_reads_back_as_zeros_in_the_gap() {
+        let cipher = test_cipher();
+        let path = temp_file_path("sparse-gap");
+        cipher.init_file(&path).unwrap();
+
+        cipher.write(&path, 0, b"abc").unwrap();
+        let gap_start = (BLOCK_SIZE * 2 + 5) as u64;
+        cipher.write(&path, gap_start, b"xyz").unwrap();
+
+        let gap = cipher.read(&path, 3, (gap_start - 3) as u32).unwrap();
+        assert!(gap.iter().all(|&b| b == 0));
+
+        let tail = cipher.read(&path, gap_start, 3).unwrap();
+        assert_eq!(tail, b"xyz");
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn truncate_shrink_then_grow_preserves_boundary_bytes() {
+        let cipher = test_cipher();
+        let path = temp_file_path("truncate");
+        cipher.init_file(&path).unwrap();
+
+        cipher.write(&path, 0, &[1u8; BLOCK_SIZE + 10]).unwrap();
+        cipher.truncate(&path, (BLOCK_SIZE + 4) as u64).unwrap();
+        assert_eq!(cipher.plaintext_len(&path).unwrap(), (BLOCK_SIZE + 4) as u64);
+
+        cipher.truncate(&path, (BLOCK_SIZE + 20) as u64).unwrap();
+        let kept = cipher.read(&path, BLOCK_SIZE as u64, 4).unwrap();
+        assert_eq!(kept, [1u8; 4]);
+        let tail = cipher.read(&path, (BLOCK_SIZE + 4) as u64, 16).unwrap();
+        assert!(tail.iter().all(|&b| b == 0));
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn overwriting_a_block_does_not_reuse_a_nonce() {
+        let cipher = test_cipher();
+        let path = temp_file_path("nonce-reuse");
+        cipher.init_file(&path).unwrap();
+
+        cipher.write(&path, 0, &[0xAAu8; BLOCK_SIZE]).unwrap();
+        let mut file = fs::File::open(&path).unwrap();
+        let header = cipher.read_header(&mut file).unwrap();
+        let (gen_before, _) = cipher.read_block(&mut file, &header, 0).unwrap();
+
+        cipher.write(&path, 0, &[0xBBu8; BLOCK_SIZE]).unwrap();
+        let mut file = fs::File::open(&path).unwrap();
+        let header = cipher.read_header(&mut file).unwrap();
+        let (gen_after, plaintext) = cipher.read_block(&mut file, &header, 0).unwrap();
+
+        assert_ne!(gen_before, gen_after);
+        assert_eq!(plaintext, vec![0xBBu8; BLOCK_SIZE]);
+
+        fs::remove_file(&path).ok();
+    }
+}
@@ -1,35 +1,68 @@
+mod backend;
+mod crypto;
+mod mountcheck;
+mod persist;
+mod webdav;
+
+use backend::{Backend, FsBackend};
 use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyDirectory,
-    ReplyEntry, ReplyOpen, ReplyData, Request,
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow,
 };
 use libc::ENOENT;
 use std::collections::HashMap;
 use std::env;
 use std::ffi::OsStr;
 use std::fs;
-use std::io::Read;
-use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const TTL: Duration = Duration::from_secs(1);
 
+// How many mutating operations to allow between periodic persists of the
+// inode index, in addition to the persist on unmount.
+const PERSIST_INTERVAL: u64 = 50;
+
 struct PassthroughFS {
-    source: PathBuf,
+    backend: Arc<FsBackend>,
     inode_map: Mutex<HashMap<u64, PathBuf>>,
     next_inode: Mutex<u64>,
+    ops_since_persist: Mutex<u64>,
 }
 
 impl PassthroughFS {
-    fn new(source: PathBuf) -> Self {
-        let mut inode_map = HashMap::new();
-        inode_map.insert(1, source.clone());
-        
+    fn new(backend: Arc<FsBackend>) -> Self {
+        let (inode_map, next_inode) = persist::load(&backend.source);
+
         PassthroughFS {
-            source,
+            backend,
             inode_map: Mutex::new(inode_map),
-            next_inode: Mutex::new(2),
+            next_inode: Mutex::new(next_inode),
+            ops_since_persist: Mutex::new(0),
+        }
+    }
+
+    /// Writes the inode index to disk now.
+    fn persist(&self) {
+        let map = self.inode_map.lock().unwrap();
+        let next_inode = *self.next_inode.lock().unwrap();
+        if let Err(e) = persist::save(&self.backend.source, &map, next_inode) {
+            eprintln!("persist: failed to save inode index: {}", e);
+        }
+    }
+
+    /// Called after a mutating operation; persists the inode index every
+    /// `PERSIST_INTERVAL` calls so a crash loses at most a small window of
+    /// inode assignments.
+    fn maybe_persist(&self) {
+        let mut ops = self.ops_since_persist.lock().unwrap();
+        *ops += 1;
+        if *ops >= PERSIST_INTERVAL {
+            *ops = 0;
+            drop(ops);
+            self.persist();
         }
     }
 
@@ -54,41 +87,45 @@ impl PassthroughFS {
         map.get(&ino).cloned()
     }
 
+    /// Drops a removed path's entry from the inode map so a later create
+    /// under the same name doesn't resolve to a stale inode.
+    fn forget_path(&self, path: &Path) {
+        let mut map = self.inode_map.lock().unwrap();
+        map.retain(|_, p| p != path);
+    }
+
     fn get_file_attr(&self, path: &Path) -> Result<FileAttr, i32> {
-        match fs::metadata(path) {
-            Ok(metadata) => {
-                let kind = if metadata.is_dir() {
-                    FileType::Directory
-                } else if metadata.is_file() {
-                    FileType::RegularFile
-                } else if metadata.is_symlink() {
-                    FileType::Symlink
-                } else {
-                    FileType::RegularFile
-                };
+        let attr = self.backend.getattr(path)?;
+        let ino = self.get_inode(path);
+        Ok(to_fuse_attr(ino, attr))
+    }
+}
 
-                let ino = self.get_inode(path);
-
-                Ok(FileAttr {
-                    ino,
-                    size: metadata.len(),
-                    blocks: metadata.blocks(),
-                    atime: metadata.accessed().unwrap_or(UNIX_EPOCH),
-                    mtime: metadata.modified().unwrap_or(UNIX_EPOCH),
-                    ctime: SystemTime::now(),
-                    crtime: UNIX_EPOCH,
-                    kind,
-                    perm: metadata.mode() as u16,
-                    nlink: metadata.nlink() as u32,
-                    uid: metadata.uid(),
-                    gid: metadata.gid(),
-                    rdev: metadata.rdev() as u32,
-                    flags: 0,
-                    blksize: metadata.blksize() as u32,
-                })
-            }
-            Err(_) => Err(ENOENT),
-        }
+/// Stamps a backend-agnostic `Attr` with the FUSE-specific inode number it
+/// was resolved to.
+fn to_fuse_attr(ino: u64, attr: backend::Attr) -> FileAttr {
+    FileAttr {
+        ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: attr.atime,
+        mtime: attr.mtime,
+        ctime: SystemTime::now(),
+        crtime: UNIX_EPOCH,
+        kind: attr.kind,
+        perm: attr.perm,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev,
+        flags: 0,
+        blksize: attr.blksize,
+    }
+}
+
+impl Drop for PassthroughFS {
+    fn drop(&mut self) {
+        self.persist();
     }
 }
 
@@ -103,9 +140,15 @@ impl Filesystem for PassthroughFS {
                 return;
             }
         };
-        
-        let file_path = parent_path.join(name);
-        
+
+        let file_path = match self.backend.lookup(&parent_path, name) {
+            Ok(p) => p,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
         match self.get_file_attr(&file_path) {
             Ok(attr) => {
                 reply.entry(&TTL, &attr, 0);
@@ -157,30 +200,10 @@ impl Filesystem for PassthroughFS {
                 return;
             }
         };
-        
-        match fs::File::open(&path) {
-            Ok(mut file) => {
-                use std::io::Seek;
-                
-                if file.seek(std::io::SeekFrom::Start(offset as u64)).is_err() {
-                    reply.error(libc::EIO);
-                    return;
-                }
-                
-                let mut buffer = vec![0; size as usize];
-                match file.read(&mut buffer) {
-                    Ok(n) => {
-                        buffer.truncate(n);
-                        reply.data(&buffer);
-                    }
-                    Err(_) => {
-                        reply.error(libc::EIO);
-                    }
-                }
-            }
-            Err(_) => {
-                reply.error(ENOENT);
-            }
+
+        match self.backend.read(&path, offset as u64, size) {
+            Ok(buffer) => reply.data(&buffer),
+            Err(e) => reply.error(e),
         }
     }
 
@@ -201,11 +224,11 @@ impl Filesystem for PassthroughFS {
                 return;
             }
         };
-        
-        let entries: Vec<_> = match fs::read_dir(&path) {
-            Ok(entries) => entries.filter_map(|e| e.ok()).collect(),
-            Err(_) => {
-                reply.error(ENOENT);
+
+        let entries = match self.backend.readdir(&path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                reply.error(e);
                 return;
             }
         };
@@ -224,73 +247,450 @@ impl Filesystem for PassthroughFS {
 
         for entry in entries.iter() {
             if offset <= current_offset {
-                let entry_path = entry.path();
-                
-                if let Ok(metadata) = entry.metadata() {
-                    let kind = if metadata.is_dir() {
-                        FileType::Directory
-                    } else {
-                        FileType::RegularFile
-                    };
-                    
-                    let entry_ino = self.get_inode(&entry_path);
-                    
-                    let full = reply.add(
-                        entry_ino,
-                        current_offset + 1,
-                        kind,
-                        entry.file_name(),
-                    );
-                    
-                    if full {
-                        break;
-                    }
+                let entry_ino = self.get_inode(&entry.path);
+
+                let full = reply.add(entry_ino, current_offset + 1, entry.attr.kind, &entry.name);
+
+                if full {
+                    break;
                 }
             }
             current_offset += 1;
         }
-        
+
         reply.ok();
     }
 
     fn open(&mut self, _req: &Request, _ino: u64, _flags: i32, reply: ReplyOpen) {
         reply.opened(0, 0);
     }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        println!("readlink: ino={}", ino);
+
+        let path = match self.get_path(ino) {
+            Some(p) => p,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        match fs::read_link(&path) {
+            Ok(target) => reply.data(target.as_os_str().as_encoded_bytes()),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        println!("create: parent={}, name={:?}", parent, name);
+
+        let parent_path = match self.get_path(parent) {
+            Some(p) => p,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let file_path = parent_path.join(name);
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).mode(mode);
+        if flags & libc::O_EXCL != 0 {
+            options.create_new(true);
+        } else {
+            options.create(true);
+            // Ciphertext truncation is handled below via the header, not a
+            // raw file truncate.
+            options.truncate(flags & libc::O_TRUNC != 0 && self.backend.cipher.is_none());
+        }
+
+        match options.open(&file_path) {
+            Ok(_) => {
+                if let Some(cipher) = &self.backend.cipher {
+                    if cipher.plaintext_len(&file_path).is_err() {
+                        if let Err(e) = cipher.init_file(&file_path) {
+                            eprintln!("create: failed to init encrypted file: {}", e);
+                            reply.error(libc::EIO);
+                            return;
+                        }
+                    } else if flags & libc::O_TRUNC != 0 {
+                        if let Err(e) = cipher.truncate(&file_path, 0) {
+                            eprintln!("create: failed to truncate encrypted file: {}", e);
+                            reply.error(libc::EIO);
+                            return;
+                        }
+                    }
+                }
+
+                match self.get_file_attr(&file_path) {
+                    Ok(attr) => {
+                        self.maybe_persist();
+                        reply.created(&TTL, &attr, 0, 0, 0);
+                    }
+                    Err(e) => reply.error(e),
+                }
+            }
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        println!("write: ino={}, offset={}, size={}", ino, offset, data.len());
+
+        let path = match self.get_path(ino) {
+            Some(p) => p,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        match self.backend.write(&path, offset as u64, data) {
+            Ok(n) => reply.written(n),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        println!("mkdir: parent={}, name={:?}", parent, name);
+
+        let parent_path = match self.get_path(parent) {
+            Some(p) => p,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let dir_path = parent_path.join(name);
+
+        match fs::create_dir(&dir_path) {
+            Ok(()) => {
+                let _ = fs::set_permissions(&dir_path, fs::Permissions::from_mode(mode));
+                match self.get_file_attr(&dir_path) {
+                    Ok(attr) => {
+                        self.maybe_persist();
+                        reply.entry(&TTL, &attr, 0);
+                    }
+                    Err(e) => reply.error(e),
+                }
+            }
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        println!("rmdir: parent={}, name={:?}", parent, name);
+
+        let parent_path = match self.get_path(parent) {
+            Some(p) => p,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let dir_path = parent_path.join(name);
+
+        match fs::remove_dir(&dir_path) {
+            Ok(()) => {
+                self.forget_path(&dir_path);
+                self.maybe_persist();
+                reply.ok();
+            }
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        println!("unlink: parent={}, name={:?}", parent, name);
+
+        let parent_path = match self.get_path(parent) {
+            Some(p) => p,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let file_path = parent_path.join(name);
+
+        match fs::remove_file(&file_path) {
+            Ok(()) => {
+                self.forget_path(&file_path);
+                self.maybe_persist();
+                reply.ok();
+            }
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        println!("rename: parent={}, name={:?} -> newparent={}, newname={:?}", parent, name, newparent, newname);
+
+        let (old_parent_path, new_parent_path) = match (self.get_path(parent), self.get_path(newparent)) {
+            (Some(o), Some(n)) => (o, n),
+            _ => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let old_path = old_parent_path.join(name);
+        let new_path = new_parent_path.join(newname);
+
+        match fs::rename(&old_path, &new_path) {
+            Ok(()) => {
+                {
+                    let mut map = self.inode_map.lock().unwrap();
+                    for p in map.values_mut() {
+                        if let Ok(suffix) = p.strip_prefix(&old_path) {
+                            *p = new_path.join(suffix);
+                        }
+                    }
+                }
+                self.maybe_persist();
+                reply.ok();
+            }
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        println!("setattr: ino={}", ino);
+
+        let path = match self.get_path(ino) {
+            Some(p) => p,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        if let Some(mode) = mode {
+            if let Err(e) = fs::set_permissions(&path, fs::Permissions::from_mode(mode)) {
+                reply.error(e.raw_os_error().unwrap_or(libc::EIO));
+                return;
+            }
+        }
+
+        if uid.is_some() || gid.is_some() {
+            // -1 (i.e. u32::MAX cast to uid_t/gid_t) tells chown(2) to leave
+            // that field unchanged.
+            let c_path = match std::ffi::CString::new(path.as_os_str().as_encoded_bytes()) {
+                Ok(c_path) => c_path,
+                Err(_) => {
+                    reply.error(libc::EINVAL);
+                    return;
+                }
+            };
+            let result = unsafe { libc::chown(c_path.as_ptr(), uid.unwrap_or(u32::MAX), gid.unwrap_or(u32::MAX)) };
+            if result != 0 {
+                reply.error(std::io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO));
+                return;
+            }
+        }
+
+        if let Some(size) = size {
+            let result = match &self.backend.cipher {
+                Some(cipher) => cipher.truncate(&path, size).map_err(|_| std::io::Error::from_raw_os_error(libc::EIO)),
+                None => fs::OpenOptions::new().write(true).open(&path).and_then(|f| f.set_len(size)),
+            };
+            if let Err(e) = result {
+                reply.error(e.raw_os_error().unwrap_or(libc::EIO));
+                return;
+            }
+        }
+
+        match self.get_file_attr(&path) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        println!("flush: ino={}", ino);
+        reply.ok();
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        println!("fsync: ino={}", ino);
+
+        match self.get_path(ino) {
+            Some(path) => match fs::File::open(&path).and_then(|f| f.sync_all()) {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+            },
+            None => reply.error(ENOENT),
+        }
+    }
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    
-    if args.len() != 3 {
-        eprintln!("Usage: {} <source> <mountpoint>", args[0]);
-        eprintln!("Example: {} /mnt/kriptofs-storage $HOME/Protected", args[0]);
+    let raw_args: Vec<String> = env::args().collect();
+    let force_unmount = raw_args.iter().any(|a| a == "--force-unmount");
+
+    let mut webdav_addr: Option<std::net::SocketAddr> = None;
+    let mut positional: Vec<&String> = Vec::new();
+    let mut iter = raw_args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--force-unmount" => {}
+            "--webdav" => {
+                let addr = match iter.next() {
+                    Some(a) => a,
+                    None => {
+                        eprintln!("Error: --webdav requires an address, e.g. --webdav 127.0.0.1:8080");
+                        std::process::exit(1);
+                    }
+                };
+                webdav_addr = match addr.parse() {
+                    Ok(addr) => Some(addr),
+                    Err(e) => {
+                        eprintln!("Error: invalid --webdav address {:?}: {}", addr, e);
+                        std::process::exit(1);
+                    }
+                };
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    if positional.is_empty() || positional.len() > 2 || (positional.len() == 1 && webdav_addr.is_none()) {
+        eprintln!("Usage: {} [--force-unmount] [--webdav <addr>] <source> [mountpoint]", raw_args[0]);
+        eprintln!("Example: {} /mnt/kriptofs-storage $HOME/Protected", raw_args[0]);
+        eprintln!("Set KRIPTOFS_PASSPHRASE to enable transparent encryption.");
+        eprintln!("--force-unmount unmounts a stale KriptoFS instance already at <mountpoint>.");
+        eprintln!("--webdav <addr> serves the same tree over HTTP instead of, or alongside, the FUSE mount.");
+        eprintln!("<mountpoint> may be omitted when --webdav is the only frontend in use.");
         std::process::exit(1);
     }
 
-    let source = PathBuf::from(&args[1]);
-    let mountpoint = &args[2];
+    let source = PathBuf::from(positional[0]);
+    let mountpoint = positional.get(1).map(|s| s.as_str());
 
     if !source.exists() {
         eprintln!("Error: Source directory does not exist: {:?}", source);
         std::process::exit(1);
     }
 
+    if let Some(mountpoint) = mountpoint {
+        if mountcheck::is_target_mounted(Path::new(mountpoint)) {
+            if force_unmount {
+                if let Err(e) = mountcheck::force_unmount_stale(Path::new(mountpoint)) {
+                    eprintln!("Error: failed to unmount stale instance at {}: {}", mountpoint, e);
+                    std::process::exit(1);
+                }
+            } else {
+                eprintln!(
+                    "Error: {} is already a mount point. Pass --force-unmount to replace a stale KriptoFS mount.",
+                    mountpoint
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if mountcheck::is_source_mounted(&source) {
+        eprintln!("Warning: source {:?} is itself a mount point; proceeding anyway.", source);
+    }
+
+    let cipher = match env::var("KRIPTOFS_PASSPHRASE") {
+        Ok(passphrase) => match crypto::derive_repo_key(&source, &passphrase) {
+            Ok(key) => Some(crypto::FileCipher::new(key)),
+            Err(e) => {
+                eprintln!("Error: failed to derive encryption key: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Err(_) => None,
+    };
+
     println!("=================================");
     println!("KriptoFS POC v0.3 - Fixed Inode");
     println!("=================================");
     println!("Source: {:?}", source);
-    println!("Mountpoint: {}", mountpoint);
-    println!();
-    println!("Mounting... (Ctrl+C to unmount)");
-    println!();
-
-    let options = vec![
-        MountOption::RW,
-        MountOption::FSName("kriptofs".to_string()),
-        MountOption::AutoUnmount,
-    ];
-
-    let fs = PassthroughFS::new(source);
-    
-    fuser::mount2(fs, mountpoint, &options).unwrap();
+    println!("Encryption: {}", if cipher.is_some() { "enabled" } else { "disabled" });
+
+    let backend = Arc::new(FsBackend::new(source, cipher));
+
+    if let Some(addr) = webdav_addr {
+        println!("WebDAV: serving on {}", addr);
+        webdav::serve(addr, backend.clone());
+    }
+
+    if let Some(mountpoint) = mountpoint {
+        println!("Mountpoint: {}", mountpoint);
+        println!();
+        println!("Mounting... (Ctrl+C to unmount)");
+        println!();
+
+        let options = vec![
+            MountOption::RW,
+            MountOption::FSName("kriptofs".to_string()),
+            MountOption::Subtype("kriptofs".to_string()),
+            MountOption::AutoUnmount,
+        ];
+
+        let fs = PassthroughFS::new(backend);
+
+        fuser::mount2(fs, mountpoint, &options).unwrap();
+    } else {
+        println!();
+        println!("No mountpoint given; serving WebDAV only. Ctrl+C to stop.");
+        loop {
+            std::thread::park();
+        }
+    }
 }
@@ -0,0 +1,85 @@
+//! Mount-state guard: checks `/proc/mounts` so we can refuse to mount onto
+//! an already-busy target and notice when the source itself is a mount
+//! point.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// A single `/proc/mounts` entry: mount target and filesystem type.
+struct Mount {
+    target: String,
+    fstype: String,
+}
+
+fn read_mounts() -> Vec<Mount> {
+    let contents = match fs::read_to_string("/proc/mounts") {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("mountcheck: failed to read /proc/mounts: {}", e);
+            return Vec::new();
+        }
+    };
+
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let _source = fields.next()?;
+            let target = fields.next()?.to_string();
+            let fstype = fields.next()?.to_string();
+            Some(Mount { target, fstype })
+        })
+        .collect()
+}
+
+fn canonical(path: &Path) -> String {
+    fs::canonicalize(path)
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.to_string_lossy().into_owned())
+}
+
+/// Returns true if `path` is currently a mount target in `/proc/mounts`.
+pub fn is_target_mounted(path: &Path) -> bool {
+    let target = canonical(path);
+    read_mounts().iter().any(|m| m.target == target)
+}
+
+/// Returns true if `path` is itself a mount target in `/proc/mounts`, i.e.
+/// the backing directory is another live mount. Ancestors of `path` and the
+/// mount source fields are not checked.
+pub fn is_source_mounted(path: &Path) -> bool {
+    let source = canonical(path);
+    read_mounts().iter().any(|m| m.target == source)
+}
+
+/// Returns the existing KriptoFS mount at `path`, if any.
+fn kriptofs_mount_at(path: &Path) -> Option<Mount> {
+    let target = canonical(path);
+    read_mounts()
+        .into_iter()
+        .find(|m| m.target == target && m.fstype == "fuse.kriptofs")
+}
+
+/// Unmounts a stale KriptoFS instance at `path`, if one is mounted there.
+/// Returns `Ok(())` if nothing was mounted or the unmount succeeded.
+pub fn force_unmount_stale(path: &Path) -> Result<(), String> {
+    if kriptofs_mount_at(path).is_none() {
+        if is_target_mounted(path) {
+            return Err(format!("{:?} is mounted, but not by KriptoFS", path));
+        }
+        return Ok(());
+    }
+
+    let status = Command::new("fusermount")
+        .arg("-u")
+        .arg(path)
+        .status()
+        .map_err(|e| format!("failed to run fusermount: {}", e))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("fusermount -u {:?} exited with {}", path, status))
+    }
+}
@@ -0,0 +1,96 @@
+//! Persists the inode->path table to a compressed index file in
+//! `crypto::meta_dir` so inode numbers survive remounts instead of being
+//! rebuilt from scratch each time.
+
+use crate::crypto;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const INDEX_NAME: &str = ".kriptofs-index";
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Serialize, Deserialize)]
+struct IndexRecord {
+    ino: u64,
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Index {
+    records: Vec<IndexRecord>,
+    next_inode: u64,
+}
+
+fn index_path(source: &Path) -> PathBuf {
+    crypto::meta_dir(source).join(INDEX_NAME)
+}
+
+/// Loads the persisted inode map, pruning any entries whose path no longer
+/// exists. Returns `(inode_map, next_inode)`, with `next_inode` preserved as
+/// the max seen inode + 1 so numbers are never reused even after pruning.
+pub fn load(source: &Path) -> (HashMap<u64, PathBuf>, u64) {
+    let mut map = HashMap::new();
+    map.insert(1, source.to_path_buf());
+    let mut next_inode = 2;
+
+    let compressed = match fs::read(index_path(source)) {
+        Ok(bytes) => bytes,
+        Err(_) => return (map, next_inode),
+    };
+
+    let decoded = match zstd::decode_all(compressed.as_slice()) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("persist: failed to decompress inode index: {}", e);
+            return (map, next_inode);
+        }
+    };
+
+    let index: Index = match bincode::deserialize(&decoded) {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("persist: failed to parse inode index: {}", e);
+            return (map, next_inode);
+        }
+    };
+
+    let mut max_seen = 1;
+    for record in index.records {
+        if record.ino == 1 || record.path.symlink_metadata().is_err() {
+            continue;
+        }
+        max_seen = max_seen.max(record.ino);
+        map.insert(record.ino, record.path);
+    }
+    next_inode = next_inode.max(max_seen + 1).max(index.next_inode);
+
+    (map, next_inode)
+}
+
+/// Writes the current inode map to the compressed index file, atomically
+/// via a rename so a crash mid-write can't corrupt the existing index.
+pub fn save(source: &Path, map: &HashMap<u64, PathBuf>, next_inode: u64) -> io::Result<()> {
+    let index = Index {
+        records: map
+            .iter()
+            .map(|(&ino, path)| IndexRecord { ino, path: path.clone() })
+            .collect(),
+        next_inode,
+    };
+
+    let encoded = bincode::serialize(&index)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let compressed = zstd::encode_all(encoded.as_slice(), ZSTD_LEVEL)?;
+
+    fs::create_dir_all(crypto::meta_dir(source))?;
+
+    let final_path = index_path(source);
+    let tmp_path = final_path.with_extension("tmp");
+    fs::write(&tmp_path, compressed)?;
+    fs::rename(&tmp_path, &final_path)?;
+
+    Ok(())
+}
@@ -0,0 +1,226 @@
+//! Optional WebDAV frontend, serving the same backend the FUSE mount uses
+//! (decrypted, when encryption is configured) over HTTP.
+
+use crate::backend::{Backend, FsBackend};
+use dav_server::davpath::DavPath;
+use dav_server::fs::{
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsResult, OpenOptions,
+    ReadDirMeta,
+};
+use dav_server::{fakels::FakeLs, DavHandler};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[derive(Clone)]
+struct KriptoMeta {
+    attr: crate::backend::Attr,
+}
+
+impl std::fmt::Debug for KriptoMeta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KriptoMeta").field("len", &self.attr.size).finish()
+    }
+}
+
+impl DavMetaData for KriptoMeta {
+    fn len(&self) -> u64 {
+        self.attr.size
+    }
+    fn modified(&self) -> FsResult<SystemTime> {
+        Ok(self.attr.mtime)
+    }
+    fn is_dir(&self) -> bool {
+        self.attr.kind == fuser::FileType::Directory
+    }
+    fn is_file(&self) -> bool {
+        self.attr.kind == fuser::FileType::RegularFile
+    }
+    fn is_symlink(&self) -> bool {
+        self.attr.kind == fuser::FileType::Symlink
+    }
+}
+
+struct KriptoDirEntry {
+    name: Vec<u8>,
+    meta: KriptoMeta,
+}
+
+impl std::fmt::Debug for KriptoDirEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KriptoDirEntry").finish()
+    }
+}
+
+impl DavDirEntry for KriptoDirEntry {
+    fn name(&self) -> Vec<u8> {
+        self.name.clone()
+    }
+    fn metadata(&self) -> FsFuture<Box<dyn DavMetaData>> {
+        let meta = self.meta.clone();
+        Box::pin(async move { Ok(Box::new(meta) as Box<dyn DavMetaData>) })
+    }
+}
+
+struct KriptoDavFile {
+    backend: Arc<FsBackend>,
+    path: PathBuf,
+    pos: u64,
+}
+
+impl std::fmt::Debug for KriptoDavFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KriptoDavFile").field("path", &self.path).finish()
+    }
+}
+
+impl DavFile for KriptoDavFile {
+    fn metadata(&mut self) -> FsFuture<Box<dyn DavMetaData>> {
+        let backend = self.backend.clone();
+        let path = self.path.clone();
+        Box::pin(async move {
+            let attr = backend.getattr(&path).map_err(|_| FsError::GeneralFailure)?;
+            Ok(Box::new(KriptoMeta { attr }) as Box<dyn DavMetaData>)
+        })
+    }
+
+    fn write_bytes(&mut self, buf: bytes::Bytes) -> FsFuture<()> {
+        Box::pin(async move {
+            self.backend.write(&self.path, self.pos, &buf).map_err(|_| FsError::GeneralFailure)?;
+            self.pos += buf.len() as u64;
+            Ok(())
+        })
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<bytes::Bytes> {
+        Box::pin(async move {
+            let data = self
+                .backend
+                .read(&self.path, self.pos, count as u32)
+                .map_err(|_| FsError::GeneralFailure)?;
+            self.pos += data.len() as u64;
+            Ok(bytes::Bytes::from(data))
+        })
+    }
+
+    fn seek(&mut self, pos: std::io::SeekFrom) -> FsFuture<u64> {
+        let attr_len = self.backend.getattr(&self.path).map(|a| a.size).unwrap_or(0);
+        let new_pos = match pos {
+            std::io::SeekFrom::Start(p) => p,
+            std::io::SeekFrom::End(p) => (attr_len as i64 + p).max(0) as u64,
+            std::io::SeekFrom::Current(p) => (self.pos as i64 + p).max(0) as u64,
+        };
+        self.pos = new_pos;
+        Box::pin(async move { Ok(new_pos) })
+    }
+
+    fn flush(&mut self) -> FsFuture<()> {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+/// Serves the backend's tree (decrypted, when `backend.cipher` is set) over
+/// WebDAV, for the small slice of the protocol clients actually
+/// need: listing, reading, and writing files.
+#[derive(Clone)]
+pub struct KriptoDavFs {
+    backend: Arc<FsBackend>,
+}
+
+impl KriptoDavFs {
+    pub fn new(backend: Arc<FsBackend>) -> Self {
+        KriptoDavFs { backend }
+    }
+
+    fn resolve(&self, davpath: &DavPath) -> PathBuf {
+        let relative = davpath.as_pathbuf();
+        let relative = relative.strip_prefix("/").unwrap_or(&relative);
+        self.backend.source.join(relative)
+    }
+}
+
+impl std::fmt::Debug for KriptoDavFs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KriptoDavFs").finish()
+    }
+}
+
+impl DavFileSystem for KriptoDavFs {
+    fn open<'a>(&'a self, davpath: &'a DavPath, _options: OpenOptions) -> FsFuture<Box<dyn DavFile>> {
+        let backend = self.backend.clone();
+        let path = self.resolve(davpath);
+        Box::pin(async move {
+            backend.create(&path).map_err(|_| FsError::GeneralFailure)?;
+            Ok(Box::new(KriptoDavFile { backend, path, pos: 0 }) as Box<dyn DavFile>)
+        })
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        davpath: &'a DavPath,
+        _meta: ReadDirMeta,
+    ) -> FsFuture<std::pin::Pin<Box<dyn futures_core::Stream<Item = Box<dyn DavDirEntry>> + Send>>> {
+        let backend = self.backend.clone();
+        let path = self.resolve(davpath);
+        Box::pin(async move {
+            let entries = backend.readdir(&path).map_err(|_| FsError::GeneralFailure)?;
+            let items: Vec<Box<dyn DavDirEntry>> = entries
+                .into_iter()
+                .map(|e| {
+                    Box::new(KriptoDirEntry {
+                        name: e.name.to_string_lossy().into_owned().into_bytes(),
+                        meta: KriptoMeta { attr: e.attr },
+                    }) as Box<dyn DavDirEntry>
+                })
+                .collect();
+            Ok(Box::pin(futures_util::stream::iter(items))
+                as std::pin::Pin<Box<dyn futures_core::Stream<Item = Box<dyn DavDirEntry>> + Send>>)
+        })
+    }
+
+    fn metadata<'a>(&'a self, davpath: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
+        let backend = self.backend.clone();
+        let path = self.resolve(davpath);
+        Box::pin(async move {
+            let attr = backend.getattr(&path).map_err(|_| FsError::GeneralFailure)?;
+            Ok(Box::new(KriptoMeta { attr }) as Box<dyn DavMetaData>)
+        })
+    }
+}
+
+/// Starts the WebDAV listener on a background thread with its own Tokio
+/// runtime (the rest of KriptoFS has no async runtime of its own).
+pub fn serve(addr: SocketAddr, backend: Arc<FsBackend>) {
+    std::thread::spawn(move || {
+        let rt = match tokio::runtime::Runtime::new() {
+            Ok(rt) => rt,
+            Err(e) => {
+                eprintln!("webdav: failed to start runtime: {}", e);
+                return;
+            }
+        };
+
+        rt.block_on(async move {
+            let davfs = KriptoDavFs::new(backend);
+            let handler = DavHandler::builder()
+                .filesystem(Box::new(davfs))
+                .locksystem(FakeLs::new())
+                .build_handler();
+
+            let make_svc = hyper::service::make_service_fn(move |_conn| {
+                let handler = handler.clone();
+                async move {
+                    Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req| {
+                        let handler = handler.clone();
+                        async move { Ok::<_, std::convert::Infallible>(handler.handle(req).await) }
+                    }))
+                }
+            });
+
+            if let Err(e) = hyper::Server::bind(&addr).serve(make_svc).await {
+                eprintln!("webdav: server error: {}", e);
+            }
+        });
+    });
+}